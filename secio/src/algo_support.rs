@@ -0,0 +1,189 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! This module contains the utilities to negotiate, between two hosts, which algorithm to use
+//! for the ephemeral key agreement and for the digest (hash) used by the HMAC.
+//!
+//! Both hosts send each other an ordered, comma-separated list of algorithm names that they
+//! support, from most to least preferred. Negotiation is deterministic and doesn't require a
+//! round-trip: see `select_best` below.
+
+use std::cmp::Ordering;
+
+use error::SecioError;
+
+/// Key agreement algorithm, used for the ephemeral Diffie-Hellman exchange during the handshake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyAgreement {
+    EcdhP256,
+    EcdhP384,
+}
+
+impl KeyAgreement {
+    /// Name of this algorithm as it appears on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            KeyAgreement::EcdhP256 => "P-256",
+            KeyAgreement::EcdhP384 => "P-384",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<KeyAgreement> {
+        match name {
+            "P-256" => Some(KeyAgreement::EcdhP256),
+            "P-384" => Some(KeyAgreement::EcdhP384),
+            _ => None,
+        }
+    }
+}
+
+/// Default, ordered list of key agreement algorithms we propose if the user didn't override it.
+pub fn default_agreements() -> Vec<KeyAgreement> {
+    vec![KeyAgreement::EcdhP256, KeyAgreement::EcdhP384]
+}
+
+/// Digest algorithm, used both for the HMAC of the non-AEAD cipher suites and for deriving keys.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Digest {
+    Sha256,
+    Sha512,
+}
+
+impl Digest {
+    /// Name of this algorithm as it appears on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Digest::Sha256 => "SHA256",
+            Digest::Sha512 => "SHA512",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<Digest> {
+        match name {
+            "SHA256" => Some(Digest::Sha256),
+            "SHA512" => Some(Digest::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes of the output of this digest.
+    pub fn num_bytes(&self) -> usize {
+        match *self {
+            Digest::Sha256 => 32,
+            Digest::Sha512 => 64,
+        }
+    }
+}
+
+/// Default, ordered list of digest algorithms we propose if the user didn't override it.
+pub fn default_digests() -> Vec<Digest> {
+    vec![Digest::Sha256, Digest::Sha512]
+}
+
+/// Turns an ordered list of algorithms into the comma-separated proposition string sent on the
+/// wire.
+pub fn proposition_string<'a, I>(algorithms: I) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    algorithms.into_iter().collect::<Vec<_>>().join(",")
+}
+
+/// Given the ordering between the two handshake hashes (see the module-level documentation of
+/// `handshake` for how `oh1`/`oh2` are computed) and each side's comma-separated proposition,
+/// returns the name of the algorithm that both sides will agree on.
+///
+/// `ordering` must be `Ordering::Greater` if the local host is the "preferring" side, or
+/// `Ordering::Less` if the remote host is. Passing `Ordering::Equal` is a programmer error, since
+/// at that point the connection is to ourselves and should already have been rejected.
+pub fn select_best(
+    ordering: Ordering,
+    local: &str,
+    remote: &str,
+) -> Result<String, SecioError> {
+    let (preferred, other) = match ordering {
+        Ordering::Less => (remote, local),
+        Ordering::Greater => (local, remote),
+        Ordering::Equal => {
+            // Reaching this point would mean we're talking to ourselves with the same key.
+            return Err(SecioError::NonceVerificationFailed);
+        }
+    };
+
+    let other_choices: Vec<&str> = other.split(',').collect();
+    preferred
+        .split(',')
+        .find(|algo| other_choices.contains(algo))
+        .map(|algo| algo.to_owned())
+        .ok_or_else(|| SecioError::NoSupportIntersection("algorithm", local.to_owned(), remote.to_owned()))
+}
+
+/// Negotiates the key agreement algorithm to use, given the local and remote propositions.
+pub fn select_agreement(
+    ordering: Ordering,
+    local: &str,
+    remote: &str,
+) -> Result<KeyAgreement, SecioError> {
+    let chosen = select_best(ordering, local, remote)?;
+    KeyAgreement::from_str(&chosen).ok_or(SecioError::HandshakeParsingFailure)
+}
+
+/// Negotiates the digest algorithm to use, given the local and remote propositions.
+pub fn select_digest(
+    ordering: Ordering,
+    local: &str,
+    remote: &str,
+) -> Result<Digest, SecioError> {
+    let chosen = select_best(ordering, local, remote)?;
+    Digest::from_str(&chosen).ok_or(SecioError::HandshakeParsingFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_best_prefers_first_match_in_preferring_sides_list() {
+        // Local is preferring (`Ordering::Greater`): the chosen algorithm is the first of
+        // local's choices that also appears in remote's, even though it's not remote's first.
+        let chosen = select_best(Ordering::Greater, "A,B,C", "C,B,A").unwrap();
+        assert_eq!(chosen, "A");
+
+        // Remote is preferring (`Ordering::Less`): same list, but now remote's order wins.
+        let chosen = select_best(Ordering::Less, "A,B,C", "C,B,A").unwrap();
+        assert_eq!(chosen, "C");
+    }
+
+    #[test]
+    fn select_best_errors_without_a_common_algorithm() {
+        match select_best(Ordering::Greater, "A,B", "C,D") {
+            Err(SecioError::NoSupportIntersection(_, _, _)) => (),
+            other => panic!("expected NoSupportIntersection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn select_best_rejects_equal_ordering() {
+        match select_best(Ordering::Equal, "A", "A") {
+            Err(SecioError::NonceVerificationFailed) => (),
+            other => panic!("expected NonceVerificationFailed, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Plain encode/decode of frames, once the handshake has negotiated a cipher and a digest.
+//!
+//! Each frame on the wire is a 4-byte big-endian length prefix followed by that many bytes of
+//! ciphertext. For the CTR-mode-plus-HMAC suites, the HMAC of the ciphertext is appended at the
+//! end; for the AEAD suites, the AEAD tag takes its place instead and there is no separate HMAC
+//! pass.
+
+use std::io::ErrorKind as IoErrorKind;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Poll, Sink, StartSend, Stream};
+use ring::{constant_time, digest, hmac};
+use tokio_io::codec::length_delimited;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use error::SecioError;
+use stream_cipher::{AeadCipher, Cipher, StreamCipher};
+
+/// Default value for `SecioConfig`'s `max_frame_length`, chosen to comfortably fit the vast
+/// majority of application messages while still bounding the memory a misbehaving or malicious
+/// peer can make us allocate for a single frame.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Computes and verifies the HMAC of a frame's ciphertext.
+struct Hmac(hmac::SigningKey);
+
+impl Hmac {
+    fn from_key(digest: &'static digest::Algorithm, key: &[u8]) -> Hmac {
+        Hmac(hmac::SigningKey::new(digest, key))
+    }
+
+    #[inline]
+    fn num_bytes(&self) -> usize {
+        self.0.digest_algorithm().output_len
+    }
+
+    fn sign(&self, crypted_data: &[u8]) -> hmac::Signature {
+        hmac::sign(&self.0, crypted_data)
+    }
+
+    fn verify(&self, crypted_data: &[u8], expected_signature: &[u8]) -> Result<(), SecioError> {
+        let produced = self.sign(crypted_data);
+        constant_time::verify_slices_are_equal(produced.as_ref(), expected_signature)
+            .map_err(|_| SecioError::CipherError)
+    }
+}
+
+/// One direction (encoding or decoding) of the negotiated cipher suite.
+enum CipherState {
+    /// The original CTR-mode-plus-HMAC construction.
+    StreamCipher {
+        cipher: Box<StreamCipher>,
+        hmac: Hmac,
+    },
+    /// An AEAD construction, where the tag travels with the ciphertext instead of a separate
+    /// HMAC.
+    Aead(AeadCipher),
+}
+
+impl CipherState {
+    /// Extra bytes (HMAC digest or AEAD tag) appended to every frame in this direction.
+    fn overhead(&self) -> usize {
+        match *self {
+            CipherState::StreamCipher { ref hmac, .. } => hmac.num_bytes(),
+            CipherState::Aead(ref aead) => aead.tag_len(),
+        }
+    }
+
+    /// Encrypts `data` in place, appending the authentication tag/HMAC at the end. `data` holds
+    /// only the plaintext; the `overhead()` extra bytes are added here.
+    fn encrypt(&mut self, data: &mut BytesMut) -> Result<(), SecioError> {
+        match *self {
+            CipherState::StreamCipher { ref mut cipher, ref hmac } => {
+                cipher.apply_keystream(data);
+                let signature = hmac.sign(data);
+                data.extend_from_slice(signature.as_ref());
+                Ok(())
+            }
+            CipherState::Aead(ref mut aead) => {
+                let tag_len = aead.tag_len();
+                data.resize(data.len() + tag_len, 0);
+                aead.seal(&mut data[..])
+            }
+        }
+    }
+
+    /// Decrypts and verifies a copy of `frame`, returning the plaintext.
+    fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, SecioError> {
+        match *self {
+            CipherState::StreamCipher { ref mut cipher, ref hmac } => {
+                let hmac_len = hmac.num_bytes();
+                if frame.len() < hmac_len {
+                    return Err(SecioError::CipherError);
+                }
+                let (crypted_data, expected_signature) = frame.split_at(frame.len() - hmac_len);
+                hmac.verify(crypted_data, expected_signature)?;
+
+                let mut crypted_data = crypted_data.to_vec();
+                cipher.apply_keystream(&mut crypted_data);
+                Ok(crypted_data)
+            }
+            CipherState::Aead(ref mut aead) => {
+                if frame.len() < aead.tag_len() {
+                    return Err(SecioError::CipherError);
+                }
+                let mut buffer = frame.to_vec();
+                let plaintext_len = aead.open(&mut buffer[..])?.len();
+                buffer.truncate(plaintext_len);
+                Ok(buffer)
+            }
+        }
+    }
+}
+
+/// Implementation of `Sink` and `Stream` on top of a socket, that handles the encryption,
+/// decryption and framing.
+///
+/// To be used after the handshake has finished.
+pub struct FullCodec<S> {
+    inner: length_delimited::Framed<S>,
+    max_frame_length: usize,
+
+    encoding: CipherState,
+    decoding: CipherState,
+
+    /// An already-encrypted frame that `inner` wasn't ready to accept yet. Kept verbatim (rather
+    /// than re-encrypted from the original plaintext) so that a frame is never encrypted twice
+    /// under two different nonces/counters: once `encoding` has sealed a frame, that exact
+    /// ciphertext is what eventually goes out, however many times it needs to be retried.
+    pending: Option<Bytes>,
+}
+
+impl<S> FullCodec<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Takes control of `socket` and returns a `FullCodec` that will encode and decode frames
+    /// using the CTR-mode-plus-HMAC construction.
+    ///
+    /// Any frame whose announced length exceeds `max_frame_length` is rejected with
+    /// `SecioError::FrameTooLarge` before the buffer for it is allocated.
+    pub fn new(
+        socket: S,
+        encoding_cipher: Box<StreamCipher>,
+        encoding_hmac_key: &[u8],
+        decoding_cipher: Box<StreamCipher>,
+        decoding_hmac_key: &[u8],
+        hmac_digest: &'static digest::Algorithm,
+        max_frame_length: usize,
+    ) -> FullCodec<S> {
+        FullCodec::with_states(
+            socket,
+            CipherState::StreamCipher {
+                cipher: encoding_cipher,
+                hmac: Hmac::from_key(hmac_digest, encoding_hmac_key),
+            },
+            CipherState::StreamCipher {
+                cipher: decoding_cipher,
+                hmac: Hmac::from_key(hmac_digest, decoding_hmac_key),
+            },
+            max_frame_length,
+        )
+    }
+
+    /// Takes control of `socket` and returns a `FullCodec` that will encode and decode frames
+    /// using the given AEAD cipher suite.
+    pub fn new_aead(
+        socket: S,
+        encoding_cipher: Cipher,
+        encoding_key: &[u8],
+        encoding_iv: &[u8],
+        decoding_cipher: Cipher,
+        decoding_key: &[u8],
+        decoding_iv: &[u8],
+        max_frame_length: usize,
+    ) -> FullCodec<S> {
+        FullCodec::with_states(
+            socket,
+            CipherState::Aead(AeadCipher::new(encoding_cipher, encoding_key, encoding_iv)),
+            CipherState::Aead(AeadCipher::new(decoding_cipher, decoding_key, decoding_iv)),
+            max_frame_length,
+        )
+    }
+
+    fn with_states(
+        socket: S,
+        encoding: CipherState,
+        decoding: CipherState,
+        max_frame_length: usize,
+    ) -> FullCodec<S> {
+        FullCodec {
+            inner: length_delimited::Builder::new()
+                .length_field_length(4)
+                .max_frame_length(max_frame_length)
+                .new_framed(socket),
+            max_frame_length,
+            encoding,
+            decoding,
+            pending: None,
+        }
+    }
+
+    /// Tries to push a previously-buffered, already-encrypted frame into `inner`. Returns `true`
+    /// once `pending` is empty (whether it was flushed just now or was already empty), or `false`
+    /// if `inner` still isn't ready to accept it.
+    fn flush_pending(&mut self) -> Result<bool, SecioError> {
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => return Ok(true),
+        };
+
+        match self.inner.start_send(frame).map_err(|err| self.map_length_delimited_err(err))? {
+            ::futures::AsyncSink::Ready => Ok(true),
+            ::futures::AsyncSink::NotReady(frame) => {
+                self.pending = Some(frame);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Turns an I/O error coming out of the underlying length-delimited transport into the
+    /// right `SecioError`. The length-delimited codec only ever produces an `InvalidData` error
+    /// when the announced (or about-to-be-written) frame length is above `max_frame_length`;
+    /// anything else is a genuine I/O failure.
+    fn map_length_delimited_err(&self, err: ::std::io::Error) -> SecioError {
+        if err.kind() == IoErrorKind::InvalidData {
+            SecioError::FrameTooLarge(self.max_frame_length)
+        } else {
+            SecioError::IoError(err)
+        }
+    }
+}
+
+impl<S> Sink for FullCodec<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type SinkItem = BytesMut;
+    type SinkError = SecioError;
+
+    fn start_send(&mut self, mut item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // A buffered frame from a previous call always takes priority: until `inner` has room
+        // for it, we can't safely encrypt (and thus advance the nonce/counter for) anything new.
+        if !self.flush_pending()? {
+            return Ok(::futures::AsyncSink::NotReady(item));
+        }
+
+        if item.len() + self.encoding.overhead() > self.max_frame_length {
+            return Err(SecioError::FrameTooLarge(self.max_frame_length));
+        }
+
+        self.encoding.encrypt(&mut item)?;
+        let frame = item.freeze().into();
+
+        match self.inner.start_send(frame).map_err(|err| self.map_length_delimited_err(err))? {
+            ::futures::AsyncSink::Ready => {}
+            ::futures::AsyncSink::NotReady(frame) => {
+                // `inner` wasn't ready; buffer the frame we already sealed rather than drop it,
+                // so it goes out exactly once and the peer's expected counter never skips ahead.
+                self.pending = Some(frame);
+            }
+        }
+        Ok(::futures::AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.flush_pending()?;
+        self.inner.poll_complete().map_err(|err| self.map_length_delimited_err(err))
+    }
+}
+
+impl<S> Stream for FullCodec<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = Vec<u8>;
+    type Error = SecioError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let frame = match self.inner.poll().map_err(|err| self.map_length_delimited_err(err))? {
+            ::futures::Async::Ready(Some(frame)) => frame,
+            ::futures::Async::Ready(None) => return Ok(::futures::Async::Ready(None)),
+            ::futures::Async::NotReady => return Ok(::futures::Async::NotReady),
+        };
+
+        let plaintext = self.decoding.decrypt(&frame)?;
+        Ok(::futures::Async::Ready(Some(plaintext)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Read, Write};
+
+    /// A fixed buffer of bytes to read from, with writes silently discarded. Just enough of an
+    /// `AsyncRead + AsyncWrite` to drive a `FullCodec` in tests without a real socket.
+    struct MockIo {
+        to_read: io::Cursor<Vec<u8>>,
+    }
+
+    impl MockIo {
+        fn with_bytes(bytes: Vec<u8>) -> MockIo {
+            MockIo { to_read: io::Cursor::new(bytes) }
+        }
+    }
+
+    impl Read for MockIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.to_read.read(buf)
+        }
+    }
+
+    impl Write for MockIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockIo {}
+
+    impl AsyncWrite for MockIo {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(::futures::Async::Ready(()))
+        }
+    }
+
+    fn aead_codec(socket: MockIo, max_frame_length: usize) -> FullCodec<MockIo> {
+        let key = vec![0x11; Cipher::Aes256Gcm.key_size()];
+        let iv = vec![0x22; Cipher::Aes256Gcm.iv_size()];
+        FullCodec::new_aead(
+            socket,
+            Cipher::Aes256Gcm,
+            &key,
+            &iv,
+            Cipher::Aes256Gcm,
+            &key,
+            &iv,
+            max_frame_length,
+        )
+    }
+
+    #[test]
+    fn start_send_rejects_a_frame_above_max_frame_length() {
+        let mut codec = aead_codec(MockIo::with_bytes(Vec::new()), 16);
+
+        let item = BytesMut::from(vec![0u8; 32]);
+        match codec.start_send(item) {
+            Err(SecioError::FrameTooLarge(16)) => (),
+            other => panic!("expected FrameTooLarge(16), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn poll_rejects_a_frame_whose_announced_length_is_above_max_frame_length() {
+        // A length-delimited frame announcing 1000 bytes of body, against a max_frame_length
+        // of 16: the announced length alone must be enough to reject it.
+        let mut bytes = vec![0, 0, 0x03, 0xe8];
+        bytes.extend_from_slice(&[0; 1000]);
+        let mut codec = aead_codec(MockIo::with_bytes(bytes), 16);
+
+        match codec.poll() {
+            Err(SecioError::FrameTooLarge(16)) => (),
+            other => panic!("expected FrameTooLarge(16), got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+
+use protobuf::ProtobufError;
+
+/// Error at the SECIO layer.
+#[derive(Debug)]
+pub enum SecioError {
+    /// I/O error.
+    IoError(IoError),
+
+    /// Failed to parse one of the handshake protobuf messages.
+    HandshakeParsingFailure,
+
+    /// There is no protocol supported by both the local and remote hosts.
+    NoSupportIntersection(&'static str, String, String),
+
+    /// Failed to generate ephemeral key.
+    EphemeralKeyGenerationFailed,
+
+    /// Failed to generate the secret shared across the ephemeral keys.
+    SecretGenerationFailed,
+
+    /// The final check of the handshake failed.
+    NonceVerificationFailed,
+
+    /// Failed to parse the remote's public key from the handshake.
+    InvalidPublicKey,
+
+    /// The signature of the exchange packet doesn't verify against the remote's public key.
+    SignatureVerificationFailed,
+
+    /// Failed to sign with the local node's private key.
+    InvalidPrivateKey,
+
+    /// A frame, either announced by the remote for decoding or about to be sent for encoding,
+    /// exceeds the configured `max_frame_length`, carried here.
+    FrameTooLarge(usize),
+
+    /// Failed to decrypt or verify a frame.
+    CipherError,
+
+    /// A key file loaded with `SecioKeyPair::load_pkcs8` was truncated, had an unrecognized
+    /// format, or was encrypted and failed to decrypt (or no passphrase was supplied). A file
+    /// that cannot be opened is reported as `IoError` instead.
+    KeyFileCorrupt,
+}
+
+impl From<IoError> for SecioError {
+    #[inline]
+    fn from(err: IoError) -> SecioError {
+        SecioError::IoError(err)
+    }
+}
+
+impl From<ProtobufError> for SecioError {
+    #[inline]
+    fn from(_: ProtobufError) -> SecioError {
+        SecioError::HandshakeParsingFailure
+    }
+}
+
+impl Into<IoError> for SecioError {
+    fn into(self) -> IoError {
+        if let SecioError::IoError(err) = self {
+            err
+        } else {
+            IoError::new(::std::io::ErrorKind::InvalidData, self)
+        }
+    }
+}
+
+impl fmt::Display for SecioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SecioError::IoError(ref err) => write!(f, "I/O error: {}", err),
+            SecioError::HandshakeParsingFailure => {
+                write!(f, "Failed to parse one of the handshake protobuf messages")
+            }
+            SecioError::NoSupportIntersection(what, ref local, ref remote) => write!(
+                f,
+                "No support intersection for {} between the local ({}) and remote ({}) proposals",
+                what, local, remote
+            ),
+            SecioError::EphemeralKeyGenerationFailed => {
+                write!(f, "Failed to generate an ephemeral key")
+            }
+            SecioError::SecretGenerationFailed => {
+                write!(f, "Failed to generate the shared secret")
+            }
+            SecioError::NonceVerificationFailed => {
+                write!(f, "The nonce verification failed, the connection is compromised")
+            }
+            SecioError::InvalidPublicKey => write!(f, "Failed to parse the remote's public key"),
+            SecioError::SignatureVerificationFailed => {
+                write!(f, "The remote's signature does not verify against its public key")
+            }
+            SecioError::InvalidPrivateKey => {
+                write!(f, "Failed to sign with the local node's private key")
+            }
+            SecioError::FrameTooLarge(max) => {
+                write!(f, "A frame was above the configured maximum length of {} bytes", max)
+            }
+            SecioError::CipherError => {
+                write!(f, "Failed to encrypt, decrypt, or authenticate a frame")
+            }
+            SecioError::KeyFileCorrupt => {
+                write!(f, "The key file is truncated, has an unrecognized format, or the passphrase is wrong")
+            }
+        }
+    }
+}
+
+impl Error for SecioError {
+    fn description(&self) -> &str {
+        match *self {
+            SecioError::IoError(_) => "I/O error",
+            SecioError::HandshakeParsingFailure => "failed to parse handshake message",
+            SecioError::NoSupportIntersection(_, _, _) => "no supported protocol in common",
+            SecioError::EphemeralKeyGenerationFailed => "failed to generate ephemeral key",
+            SecioError::SecretGenerationFailed => "failed to generate shared secret",
+            SecioError::NonceVerificationFailed => "nonce verification failed",
+            SecioError::InvalidPublicKey => "invalid remote public key",
+            SecioError::SignatureVerificationFailed => "signature verification failed",
+            SecioError::InvalidPrivateKey => "invalid local private key",
+            SecioError::FrameTooLarge(_) => "frame length exceeds the configured maximum",
+            SecioError::CipherError => "cipher error",
+            SecioError::KeyFileCorrupt => "key file truncated, corrupt, or wrong passphrase",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SecioError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Negotiates, performs and completes the SECIO handshake.
+//!
+//! # Negotiating algorithms without a round-trip
+//!
+//! Both peers send an ordered, comma-joined preference list for each of the three algorithm
+//! categories (key agreement, cipher, digest) as part of their `Propose` message. To agree on one
+//! algorithm per category without an extra round-trip, each side computes:
+//!
+//! - `oh1 = SHA256(remote_public_key_bytes ++ local_nonce)`
+//! - `oh2 = SHA256(local_public_key_bytes ++ remote_nonce)`
+//!
+//! Whichever side's hash compares greater is the "preferring" side. For each category, the chosen
+//! algorithm is the first entry in the preferring side's ordered list that also appears anywhere
+//! in the other side's list. If `oh1 == oh2` the connection is to ourselves (same keypair) and the
+//! handshake is aborted.
+//!
+//! The "preferring" side also decides which half of the stretched key material it uses for its
+//! outgoing direction: the preferring side uses the first half to encode, the other side uses the
+//! first half to decode (see `build_codec`). This, plus the final key-confirmation round trip
+//! below, is what this implementation has in common with go-libp2p's and js-libp2p's secio.
+//!
+//! # Key confirmation
+//!
+//! Once the `FullCodec` is built, each side sends back, encrypted with the freshly negotiated
+//! cipher, the nonce it received in the peer's `Propose` message. Each side then checks that the
+//! frame it receives back matches the nonce it originally generated. This both confirms that the
+//! two sides derived matching key material and exercises the cipher before any application data
+//! is sent.
+
+use std::cmp::Ordering;
+
+use bytes::BytesMut;
+use futures::{Future, Sink, Stream};
+use libp2p_core::PublicKey;
+use ring::agreement::{Algorithm, EphemeralPrivateKey, agree_ephemeral, ECDH_P256, ECDH_P384};
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::{constant_time, hmac, signature};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+use untrusted::Input;
+
+use algo_support::{self, Digest, KeyAgreement};
+use codec::FullCodec;
+use error::SecioError;
+use stream_cipher::{self, Cipher};
+use structs_proto::{Exchange, Propose};
+use SecioConfig;
+
+/// Performs a SECIO handshake on the given socket, using the algorithms configured in `config`.
+///
+/// On success, returns a `FullCodec` ready to encrypt/decrypt application data, the remote's
+/// public key, and the ephemeral public key that was exchanged.
+pub fn handshake<S>(
+    socket: S,
+    config: SecioConfig,
+) -> Box<Future<Item = (FullCodec<S>, PublicKey, Vec<u8>), Error = SecioError>>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let rng = SystemRandom::new();
+    let mut local_nonce = [0; 16];
+    if ::ring::rand::SecureRandom::fill(&rng, &mut local_nonce).is_err() {
+        return Box::new(::futures::future::err(SecioError::EphemeralKeyGenerationFailed));
+    }
+
+    let local_public_key_bytes = config.key.to_public_key().into_protobuf_encoding();
+
+    let local_propose = Propose {
+        rand: local_nonce.to_vec(),
+        pubkey: local_public_key_bytes.clone(),
+        exchanges: algo_support::proposition_string(config.agreements_proposition()),
+        ciphers: algo_support::proposition_string(config.ciphers_proposition()),
+        hashes: algo_support::proposition_string(config.digests_proposition()),
+    };
+
+    let local_propose_bytes = match local_propose.write_to_bytes() {
+        Ok(b) => b,
+        Err(_) => return Box::new(::futures::future::err(SecioError::HandshakeParsingFailure)),
+    };
+
+    let fut = send_frame(socket, local_propose_bytes.clone())
+        .and_then(|socket| recv_frame(socket))
+        .and_then(move |(socket, remote_propose_raw)| {
+            let remote_propose = Propose::parse_from_bytes(&remote_propose_raw)?;
+            let remote_public_key = parse_remote_public_key(&remote_propose.pubkey)?;
+
+            let ordering = determine_ordering(
+                &remote_propose.pubkey,
+                &local_nonce,
+                &local_public_key_bytes,
+                &remote_propose.rand,
+            )?;
+
+            let agreement = algo_support::select_agreement(
+                ordering,
+                &algo_support::proposition_string(config.agreements_proposition()),
+                &remote_propose.exchanges,
+            )?;
+            let cipher = stream_cipher::select_cipher(
+                ordering,
+                &algo_support::proposition_string(config.ciphers_proposition()),
+                &remote_propose.ciphers,
+            )?;
+            let digest = algo_support::select_digest(
+                ordering,
+                &algo_support::proposition_string(config.digests_proposition()),
+                &remote_propose.hashes,
+            )?;
+
+            let remote_nonce = remote_propose.rand;
+
+            Ok((
+                socket,
+                config,
+                remote_public_key,
+                remote_propose_raw,
+                remote_nonce,
+                ordering,
+                agreement,
+                cipher,
+                digest,
+            ))
+        })
+        .and_then(
+            move |(socket, config, remote_public_key, remote_propose_raw, remote_nonce, ordering,
+                   agreement, cipher, digest)| {
+                let alg: &'static Algorithm = match agreement {
+                    KeyAgreement::EcdhP256 => &ECDH_P256,
+                    KeyAgreement::EcdhP384 => &ECDH_P384,
+                };
+                let rng = SystemRandom::new();
+                let ephemeral_key = EphemeralPrivateKey::generate(alg, &rng)
+                    .map_err(|_| SecioError::EphemeralKeyGenerationFailed)?;
+                let ephemeral_public_key = ephemeral_key.compute_public_key()
+                    .map_err(|_| SecioError::EphemeralKeyGenerationFailed)?
+                    .as_ref()
+                    .to_vec();
+
+                let mut to_sign = local_propose_bytes.clone();
+                to_sign.extend_from_slice(&remote_propose_raw);
+                to_sign.extend_from_slice(&ephemeral_public_key);
+                let signature = config.key.sign(&to_sign)?;
+
+                let local_exchange = Exchange {
+                    epubkey: ephemeral_public_key.clone(),
+                    signature,
+                };
+                let local_exchange_bytes = local_exchange.write_to_bytes()
+                    .map_err(|_| SecioError::HandshakeParsingFailure)?;
+
+                Ok((
+                    socket,
+                    config,
+                    remote_public_key,
+                    local_propose_bytes,
+                    remote_propose_raw,
+                    remote_nonce,
+                    ordering,
+                    ephemeral_key,
+                    ephemeral_public_key,
+                    cipher,
+                    digest,
+                    local_exchange_bytes,
+                ))
+            },
+        )
+        .and_then(|(socket, config, remote_public_key, local_propose_bytes, remote_propose_raw,
+                    remote_nonce, ordering, ephemeral_key, ephemeral_public_key, cipher, digest,
+                    local_exchange_bytes)| {
+            send_frame(socket, local_exchange_bytes).map(move |socket| {
+                (
+                    socket,
+                    config,
+                    remote_public_key,
+                    local_propose_bytes,
+                    remote_propose_raw,
+                    remote_nonce,
+                    ordering,
+                    ephemeral_key,
+                    ephemeral_public_key,
+                    cipher,
+                    digest,
+                )
+            })
+        })
+        .and_then(|(socket, config, remote_public_key, local_propose_bytes, remote_propose_raw,
+                    remote_nonce, ordering, ephemeral_key, ephemeral_public_key, cipher, digest)| {
+            recv_frame(socket).map(move |(socket, remote_exchange_raw)| {
+                (
+                    socket,
+                    config,
+                    remote_public_key,
+                    local_propose_bytes,
+                    remote_propose_raw,
+                    remote_nonce,
+                    ordering,
+                    ephemeral_key,
+                    ephemeral_public_key,
+                    cipher,
+                    digest,
+                    remote_exchange_raw,
+                )
+            })
+        })
+        .and_then(|(socket, config, remote_public_key, local_propose_bytes, remote_propose_raw,
+                    remote_nonce, ordering, ephemeral_key, ephemeral_public_key, cipher, digest,
+                    remote_exchange_raw)| {
+            let remote_exchange = Exchange::parse_from_bytes(&remote_exchange_raw)?;
+
+            let mut signed_message = remote_propose_raw;
+            signed_message.extend_from_slice(&local_propose_bytes);
+            signed_message.extend_from_slice(&remote_exchange.epubkey);
+            verify_remote_signature(&remote_public_key, &signed_message, &remote_exchange.signature)?;
+
+            let agreement_alg = ephemeral_key.algorithm();
+
+            let codec = agree_ephemeral(
+                ephemeral_key,
+                agreement_alg,
+                Input::from(&remote_exchange.epubkey),
+                SecioError::SecretGenerationFailed,
+                |shared_secret| {
+                    Ok(build_codec(socket, ordering, shared_secret, cipher, digest, config.max_frame_length))
+                },
+            )?;
+
+            Ok((codec, remote_public_key, ephemeral_public_key, remote_nonce))
+        })
+        .and_then(move |(codec, remote_public_key, ephemeral_public_key, remote_nonce)| {
+            // Key confirmation: echo back the nonce the peer sent us in its `Propose`, encrypted
+            // under the cipher we just negotiated.
+            codec.send(BytesMut::from(remote_nonce)).map(move |codec| {
+                (codec, remote_public_key, ephemeral_public_key)
+            })
+        })
+        .and_then(move |(codec, remote_public_key, ephemeral_public_key)| {
+            codec.into_future().map_err(|(err, _codec)| err).and_then(
+                move |(echoed_nonce, codec)| match echoed_nonce {
+                    Some(ref echoed_nonce)
+                        if constant_time::verify_slices_are_equal(echoed_nonce, &local_nonce).is_ok() =>
+                    {
+                        Ok((codec, remote_public_key, ephemeral_public_key))
+                    }
+                    _ => Err(SecioError::NonceVerificationFailed),
+                },
+            )
+        });
+
+    Box::new(fut)
+}
+
+/// Writes a 4-byte big-endian length prefix followed by `data`.
+fn send_frame<S>(socket: S, data: Vec<u8>) -> Box<Future<Item = S, Error = SecioError>>
+where
+    S: AsyncWrite + 'static,
+{
+    let mut framed = (data.len() as u32).to_be_bytes().to_vec();
+    framed.extend_from_slice(&data);
+    Box::new(write_all(socket, framed).map(|(socket, _)| socket).map_err(SecioError::from))
+}
+
+/// Reads a 4-byte big-endian length prefix, then that many bytes.
+fn recv_frame<S>(socket: S) -> Box<Future<Item = (S, Vec<u8>), Error = SecioError>>
+where
+    S: AsyncRead + 'static,
+{
+    let fut = read_exact(socket, [0u8; 4])
+        .map_err(SecioError::from)
+        .and_then(|(socket, len_buf)| {
+            let len = u32::from_be_bytes(len_buf) as usize;
+            read_exact(socket, vec![0u8; len]).map_err(SecioError::from)
+        });
+    Box::new(fut)
+}
+
+/// Computes `oh1`/`oh2` as described in the module documentation and returns `Ordering::Greater`
+/// if the local side is the "preferring" side, or `Ordering::Less` if the remote is.
+fn determine_ordering(
+    remote_public_key_bytes: &[u8],
+    local_nonce: &[u8],
+    local_public_key_bytes: &[u8],
+    remote_nonce: &[u8],
+) -> Result<Ordering, SecioError> {
+    let mut ctx1 = digest::Context::new(&digest::SHA256);
+    ctx1.update(remote_public_key_bytes);
+    ctx1.update(local_nonce);
+    let oh1 = ctx1.finish();
+
+    let mut ctx2 = digest::Context::new(&digest::SHA256);
+    ctx2.update(local_public_key_bytes);
+    ctx2.update(remote_nonce);
+    let oh2 = ctx2.finish();
+
+    match oh1.as_ref().cmp(oh2.as_ref()) {
+        Ordering::Equal => Err(SecioError::NonceVerificationFailed),
+        other => Ok(other),
+    }
+}
+
+fn parse_remote_public_key(bytes: &[u8]) -> Result<PublicKey, SecioError> {
+    PublicKey::from_protobuf_encoding(bytes).map_err(|_| SecioError::InvalidPublicKey)
+}
+
+fn verify_remote_signature(
+    public_key: &PublicKey,
+    message: &[u8],
+    signature_bytes: &[u8],
+) -> Result<(), SecioError> {
+    let ok = match *public_key {
+        PublicKey::Rsa(ref key) => {
+            signature::verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                Input::from(key),
+                Input::from(message),
+                Input::from(signature_bytes),
+            ).is_ok()
+        }
+        PublicKey::Ed25519(ref key) => {
+            signature::verify(
+                &signature::ED25519,
+                Input::from(key),
+                Input::from(message),
+                Input::from(signature_bytes),
+            ).is_ok()
+        }
+        #[cfg(feature = "secp256k1")]
+        PublicKey::Secp256k1(ref key) => {
+            let secp = ::secp256k1::Secp256k1::with_caps(::secp256k1::ContextFlag::VerifyOnly);
+            let pubkey = ::secp256k1::key::PublicKey::from_slice(&secp, key);
+            let msg = ::secp256k1::Message::from_slice(&digest::digest(&digest::SHA256, message).as_ref());
+            match (pubkey, msg) {
+                (Ok(pubkey), Ok(msg)) => {
+                    ::secp256k1::Signature::from_der(&secp, signature_bytes)
+                        .and_then(|sig| secp.verify(&msg, &sig, &pubkey))
+                        .is_ok()
+                }
+                _ => false,
+            }
+        }
+        #[cfg(not(feature = "secp256k1"))]
+        _ => false,
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(SecioError::SignatureVerificationFailed)
+    }
+}
+
+/// Expands the raw Diffie-Hellman shared secret into the key material both sides of the
+/// connection need (one half per direction), then builds the `FullCodec` that will be used from
+/// now on.
+///
+/// Both peers derive the same two halves of the expanded key, in the same order; which half each
+/// side uses to encode (as opposed to decode) is decided by `ordering`, exactly like the
+/// algorithm negotiation in the module documentation: the preferring side (`Ordering::Greater`)
+/// encodes with the first half, while the other side (`Ordering::Less`) encodes with the second
+/// half. Without this swap both peers would encode with the same half and every frame would fail
+/// to authenticate on the other end.
+fn build_codec<S>(
+    socket: S,
+    ordering: Ordering,
+    shared_secret: &[u8],
+    cipher: Cipher,
+    digest_algo: Digest,
+    max_frame_length: usize,
+) -> FullCodec<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let key_size = cipher.key_size();
+    let iv_size = cipher.iv_size();
+    // The AEAD suites don't need a separate MAC key: the tag is computed over the frame itself.
+    let mac_size = if cipher.is_aead() { 0 } else { digest_algo.num_bytes() };
+    let needed = 2 * (key_size + iv_size + mac_size);
+
+    let expanded = stretch_key(digest_algo, shared_secret, needed);
+    let (first_half, second_half) = expanded.split_at(needed / 2);
+    let (local_half, remote_half) = match ordering {
+        Ordering::Less => (second_half, first_half),
+        _ => (first_half, second_half),
+    };
+
+    let (local_iv, rest) = local_half.split_at(iv_size);
+    let (local_key, local_mac_key) = rest.split_at(key_size);
+
+    let (remote_iv, rest) = remote_half.split_at(iv_size);
+    let (remote_key, remote_mac_key) = rest.split_at(key_size);
+
+    if cipher.is_aead() {
+        FullCodec::new_aead(
+            socket,
+            cipher,
+            local_key,
+            local_iv,
+            cipher,
+            remote_key,
+            remote_iv,
+            max_frame_length,
+        )
+    } else {
+        let encoding_cipher = stream_cipher::ctr(cipher, local_key, local_iv);
+        let decoding_cipher = stream_cipher::ctr(cipher, remote_key, remote_iv);
+
+        let hmac_digest = match digest_algo {
+            Digest::Sha256 => &digest::SHA256,
+            Digest::Sha512 => &digest::SHA512,
+        };
+
+        FullCodec::new(
+            socket,
+            encoding_cipher,
+            local_mac_key,
+            decoding_cipher,
+            remote_mac_key,
+            hmac_digest,
+            max_frame_length,
+        )
+    }
+}
+
+/// Expands `key` into `len` bytes of key material, following the same construction as the rest
+/// of the libp2p ecosystem: repeatedly HMAC-ing the previous output together with a fixed label.
+fn stretch_key(digest_algo: Digest, key: &[u8], len: usize) -> Vec<u8> {
+    let algo = match digest_algo {
+        Digest::Sha256 => &digest::SHA256,
+        Digest::Sha512 => &digest::SHA512,
+    };
+    let signing_key = hmac::SigningKey::new(algo, key);
+
+    let seed = b"key expansion";
+    let mut result = Vec::with_capacity(len);
+    let mut a = hmac::sign(&signing_key, seed);
+
+    while result.len() < len {
+        let mut to_sign = a.as_ref().to_vec();
+        to_sign.extend_from_slice(seed);
+        let b = hmac::sign(&signing_key, &to_sign);
+        let take = ::std::cmp::min(b.as_ref().len(), len - result.len());
+        result.extend_from_slice(&b.as_ref()[..take]);
+        a = hmac::sign(&signing_key, a.as_ref());
+    }
+
+    result
+}
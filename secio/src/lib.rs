@@ -49,10 +49,9 @@
 //!         //let private_key = include_bytes!("test-rsa-private-key.pk8");
 //!         # let public_key = vec![];
 //!         //let public_key = include_bytes!("test-rsa-public-key.der").to_vec();
-//!         let upgrade = SecioConfig {
-//!             // See the documentation of `SecioKeyPair`.
-//!             key: SecioKeyPair::rsa_from_pkcs8(private_key, public_key).unwrap(),
-//!         };
+//!         // See the documentation of `SecioKeyPair`.
+//!         let key = SecioKeyPair::rsa_from_pkcs8(private_key, public_key).unwrap();
+//!         let upgrade = SecioConfig::new(key);
 //!
 //!         upgrade::map(upgrade, |out: SecioOutput<_>| out.stream)
 //!     });
@@ -107,7 +106,7 @@ use futures::stream::MapErr as StreamMapErr;
 use futures::{Future, Poll, Sink, StartSend, Stream};
 use libp2p_core::{PeerId, PublicKey};
 use ring::rand::SystemRandom;
-use ring::signature::{Ed25519KeyPair, RSAKeyPair};
+use ring::signature::{Ed25519KeyPair, RSAKeyPair, RSASigningState, RSA_PKCS1_SHA256};
 use rw_stream_sink::RwStreamSink;
 use std::error::Error;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
@@ -120,15 +119,102 @@ mod algo_support;
 mod codec;
 mod error;
 mod handshake;
+mod persist;
 mod structs_proto;
 mod stream_cipher;
 
+pub use algo_support::{Digest, KeyAgreement};
+pub use stream_cipher::Cipher;
+
 /// Implementation of the `ConnectionUpgrade` trait of `libp2p_core`. Automatically applies
 /// secio on any connection.
 #[derive(Clone)]
 pub struct SecioConfig {
     /// Private and public keys of the local node.
     pub key: SecioKeyPair,
+
+    /// Ordered list of key agreement algorithms we're willing to accept, from most to least
+    /// preferred. Defaults to `algo_support::default_agreements()`.
+    key_agreements: Vec<KeyAgreement>,
+
+    /// Ordered list of ciphers we're willing to accept, from most to least preferred. Defaults
+    /// to `stream_cipher::default_ciphers()`.
+    ciphers: Vec<Cipher>,
+
+    /// Ordered list of digest algorithms we're willing to accept, from most to least preferred.
+    /// Defaults to `algo_support::default_digests()`.
+    digests: Vec<Digest>,
+
+    /// Maximum length, in bytes, of a single frame we're willing to decode (or encode) once the
+    /// handshake has completed. Defaults to `codec::DEFAULT_MAX_FRAME_LENGTH`.
+    max_frame_length: usize,
+}
+
+impl SecioConfig {
+    /// Builds a new `SecioConfig` with the given identity and the default set of algorithm
+    /// proposals.
+    pub fn new(key: SecioKeyPair) -> SecioConfig {
+        SecioConfig {
+            key,
+            key_agreements: algo_support::default_agreements(),
+            ciphers: stream_cipher::default_ciphers(),
+            digests: algo_support::default_digests(),
+            max_frame_length: codec::DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+
+    /// Overrides the ordered list of key agreement algorithms that will be proposed during the
+    /// handshake. The first entry is the most preferred.
+    pub fn key_agreements<I>(mut self, key_agreements: I) -> Self
+    where
+        I: IntoIterator<Item = KeyAgreement>,
+    {
+        self.key_agreements = key_agreements.into_iter().collect();
+        self
+    }
+
+    /// Overrides the ordered list of ciphers that will be proposed during the handshake. The
+    /// first entry is the most preferred.
+    pub fn ciphers<I>(mut self, ciphers: I) -> Self
+    where
+        I: IntoIterator<Item = Cipher>,
+    {
+        self.ciphers = ciphers.into_iter().collect();
+        self
+    }
+
+    /// Overrides the ordered list of digest algorithms that will be proposed during the
+    /// handshake. The first entry is the most preferred.
+    pub fn digests<I>(mut self, digests: I) -> Self
+    where
+        I: IntoIterator<Item = Digest>,
+    {
+        self.digests = digests.into_iter().collect();
+        self
+    }
+
+    /// Overrides the maximum length, in bytes, of a single frame. Embedders that exchange large
+    /// application payloads can raise this; the default keeps a misbehaving or malicious peer
+    /// from making us buffer an arbitrarily large frame.
+    pub fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Ordered list of key agreement algorithm names, as advertised on the wire.
+    fn agreements_proposition(&self) -> Vec<&str> {
+        self.key_agreements.iter().map(KeyAgreement::as_str).collect()
+    }
+
+    /// Ordered list of cipher names, as advertised on the wire.
+    fn ciphers_proposition(&self) -> Vec<&str> {
+        self.ciphers.iter().map(Cipher::as_str).collect()
+    }
+
+    /// Ordered list of digest algorithm names, as advertised on the wire.
+    fn digests_proposition(&self) -> Vec<&str> {
+        self.digests.iter().map(Digest::as_str).collect()
+    }
 }
 
 /// Private and public keys of the local node.
@@ -167,12 +253,14 @@ impl SecioKeyPair {
     where
         P: Into<Vec<u8>>,
     {
-        let private = RSAKeyPair::from_pkcs8(Input::from(&private[..])).map_err(Box::new)?;
+        let private_pkcs8 = private.to_vec();
+        let private = RSAKeyPair::from_pkcs8(Input::from(&private_pkcs8[..])).map_err(Box::new)?;
 
         Ok(SecioKeyPair {
             inner: SecioKeyPairInner::Rsa {
                 public: public.into(),
                 private: Arc::new(private),
+                private_pkcs8: Arc::new(private_pkcs8),
             },
         })
     }
@@ -187,6 +275,7 @@ impl SecioKeyPair {
         Ok(SecioKeyPair {
             inner: SecioKeyPairInner::Ed25519 {
                 key_pair: Arc::new(key_pair),
+                pkcs8: Arc::new(key.as_ref().to_vec()),
             },
         })
     }
@@ -234,7 +323,7 @@ impl SecioKeyPair {
     pub fn to_public_key(&self) -> PublicKey {
         match self.inner {
             SecioKeyPairInner::Rsa { ref public, .. } => PublicKey::Rsa(public.clone()),
-            SecioKeyPairInner::Ed25519 { ref key_pair } => {
+            SecioKeyPairInner::Ed25519 { ref key_pair, .. } => {
                 PublicKey::Ed25519(key_pair.public_key_bytes().to_vec())
             }
             #[cfg(feature = "secp256k1")]
@@ -253,7 +342,54 @@ impl SecioKeyPair {
         self.to_public_key().into_peer_id()
     }
 
-    // TODO: method to save generated key on disk?
+    /// Signs a message with this keypair's private key.
+    pub(crate) fn sign(&self, message: &[u8]) -> Result<Vec<u8>, SecioError> {
+        match self.inner {
+            SecioKeyPairInner::Rsa { ref private, .. } => {
+                let mut state = RSASigningState::new(private.clone())
+                    .map_err(|_| SecioError::InvalidPrivateKey)?;
+                let mut signature = vec![0; private.public_modulus_len()];
+                let rng = SystemRandom::new();
+                state
+                    .sign(&RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .map_err(|_| SecioError::InvalidPrivateKey)?;
+                Ok(signature)
+            }
+            SecioKeyPairInner::Ed25519 { ref key_pair, .. } => {
+                Ok(key_pair.sign(message).as_ref().to_vec())
+            }
+            #[cfg(feature = "secp256k1")]
+            SecioKeyPairInner::Secp256k1 { ref private } => {
+                let secp = secp256k1::Secp256k1::with_caps(secp256k1::ContextFlag::SignOnly);
+                let digest = ::ring::digest::digest(&::ring::digest::SHA256, message);
+                let message = secp256k1::Message::from_slice(digest.as_ref())
+                    .map_err(|_| SecioError::InvalidPrivateKey)?;
+                let signature = secp.sign(&message, private)
+                    .map_err(|_| SecioError::InvalidPrivateKey)?;
+                Ok(signature.serialize_der(&secp))
+            }
+        }
+    }
+
+    /// Writes this key pair to `path`. If `passphrase` is provided, the key material is
+    /// encrypted with a key derived from it before being written; otherwise the file is
+    /// plaintext.
+    pub fn save_pkcs8<P>(&self, path: P, passphrase: Option<&str>) -> Result<(), SecioError>
+    where
+        P: AsRef<::std::path::Path>,
+    {
+        persist::save_pkcs8(self, path.as_ref(), passphrase)
+    }
+
+    /// Reads a key pair previously written with [`SecioKeyPair::save_pkcs8`]. `passphrase` must
+    /// be provided if (and only if) the file was encrypted; a wrong passphrase or a corrupt file
+    /// both result in `SecioError::KeyFileCorrupt`.
+    pub fn load_pkcs8<P>(path: P, passphrase: Option<&str>) -> Result<SecioKeyPair, SecioError>
+    where
+        P: AsRef<::std::path::Path>,
+    {
+        persist::load_pkcs8(path.as_ref(), passphrase)
+    }
 }
 
 // Inner content of `SecioKeyPair`.
@@ -263,10 +399,15 @@ enum SecioKeyPairInner {
         public: Vec<u8>,
         // We use an `Arc` so that we can clone the enum.
         private: Arc<RSAKeyPair>,
+        // Kept alongside `private` so that `SecioKeyPair::save_pkcs8` can write the key back out;
+        // `ring`'s `RSAKeyPair` doesn't expose its own PKCS8 encoding.
+        private_pkcs8: Arc<Vec<u8>>,
     },
     Ed25519 {
         // We use an `Arc` so that we can clone the enum.
         key_pair: Arc<Ed25519KeyPair>,
+        // Kept alongside `key_pair` for the same reason as `Rsa`'s `private_pkcs8`.
+        pkcs8: Arc<Vec<u8>>,
     },
     #[cfg(feature = "secp256k1")]
     Secp256k1 { private: secp256k1::key::SecretKey },
@@ -311,7 +452,7 @@ where
     ) -> Self::Future {
         debug!("Starting secio upgrade");
 
-        let fut = SecioMiddleware::handshake(incoming, self.key);
+        let fut = SecioMiddleware::handshake(incoming, self);
         let wrapped = fut.map(|(stream_sink, pubkey, ephemeral)| {
             let mapped = stream_sink.map_err(map_err as fn(_) -> _);
             SecioOutput {
@@ -348,12 +489,12 @@ where
     /// communications, plus the public key of the remote, plus the ephemeral public key.
     pub fn handshake<'a>(
         socket: S,
-        key_pair: SecioKeyPair,
+        config: SecioConfig,
     ) -> Box<Future<Item = (SecioMiddleware<S>, PublicKey, Vec<u8>), Error = SecioError> + 'a>
     where
         S: 'a,
     {
-        let fut = handshake::handshake(socket, key_pair).map(|(inner, pubkey, ephemeral)| {
+        let fut = handshake::handshake(socket, config).map(|(inner, pubkey, ephemeral)| {
             let inner = SecioMiddleware { inner };
             (inner, pubkey, ephemeral)
         });
@@ -371,12 +512,12 @@ where
 
     #[inline]
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        self.inner.start_send(item)
+        self.inner.start_send(item).map_err(Into::into)
     }
 
     #[inline]
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.inner.poll_complete()
+        self.inner.poll_complete().map_err(Into::into)
     }
 }
 
@@ -0,0 +1,307 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Saving and loading a `SecioKeyPair` to and from disk, optionally encrypted with a passphrase.
+//!
+//! # On-disk format
+//!
+//! ```text
+//! magic           4 bytes   b"SK01"
+//! encrypted       1 byte    0 or 1
+//! if encrypted:
+//!   salt          16 bytes  PBKDF2 salt
+//!   nonce         12 bytes  AES-256-GCM nonce
+//! payload         remainder key type + key material (see `encode_payload`),
+//!                           encrypted with AES-256-GCM if `encrypted == 1`
+//! ```
+//!
+//! The payload itself starts with a single byte identifying the key type, followed by
+//! length-prefixed (or, for secp256k1, fixed-size) key material, so that a plaintext file and a
+//! decrypted file are parsed identically.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use ring::aead;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use error::SecioError;
+use SecioKeyPair;
+use SecioKeyPairInner;
+
+const MAGIC: &[u8; 4] = b"SK01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+const KEY_TYPE_RSA: u8 = 0;
+const KEY_TYPE_ED25519: u8 = 1;
+const KEY_TYPE_SECP256K1: u8 = 2;
+
+/// Writes `key_pair` to `path`, encrypting it with `passphrase` if one is given.
+pub fn save_pkcs8(key_pair: &SecioKeyPair, path: &Path, passphrase: Option<&str>) -> Result<(), SecioError> {
+    let payload = encode_payload(key_pair)?;
+
+    let mut out = MAGIC.to_vec();
+    match passphrase {
+        None => {
+            out.push(0);
+            out.extend_from_slice(&payload);
+        }
+        Some(passphrase) => {
+            let rng = SystemRandom::new();
+
+            let mut salt = [0; SALT_LEN];
+            rng.fill(&mut salt).map_err(|_| SecioError::KeyFileCorrupt)?;
+            let mut nonce = [0; NONCE_LEN];
+            rng.fill(&mut nonce).map_err(|_| SecioError::KeyFileCorrupt)?;
+
+            let key = derive_key(passphrase, &salt);
+            let sealing_key =
+                aead::SealingKey::new(&aead::AES_256_GCM, &key).map_err(|_| SecioError::KeyFileCorrupt)?;
+
+            let mut in_out = payload;
+            in_out.extend_from_slice(&[0; 16]); // room for the AEAD tag
+            aead::seal_in_place(&sealing_key, &nonce, &[], &mut in_out, 16)
+                .map_err(|_| SecioError::KeyFileCorrupt)?;
+
+            out.push(1);
+            out.extend_from_slice(&salt);
+            out.extend_from_slice(&nonce);
+            out.extend_from_slice(&in_out);
+        }
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&out)?;
+    set_private_permissions(path)?;
+    Ok(())
+}
+
+/// Reads a `SecioKeyPair` previously written with [`save_pkcs8`], decrypting it with
+/// `passphrase` if the file is encrypted.
+pub fn load_pkcs8(path: &Path, passphrase: Option<&str>) -> Result<SecioKeyPair, SecioError> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+
+    if contents.len() < MAGIC.len() + 1 || &contents[..MAGIC.len()] != &MAGIC[..] {
+        return Err(SecioError::KeyFileCorrupt);
+    }
+    let mut cursor = MAGIC.len();
+    let encrypted = contents[cursor];
+    cursor += 1;
+
+    let payload = if encrypted == 0 {
+        contents[cursor..].to_vec()
+    } else {
+        let passphrase = passphrase.ok_or(SecioError::KeyFileCorrupt)?;
+
+        if contents.len() < cursor + SALT_LEN + NONCE_LEN {
+            return Err(SecioError::KeyFileCorrupt);
+        }
+        let salt = &contents[cursor..cursor + SALT_LEN];
+        cursor += SALT_LEN;
+        let nonce = &contents[cursor..cursor + NONCE_LEN];
+        cursor += NONCE_LEN;
+
+        let key = derive_key(passphrase, salt);
+        let opening_key =
+            aead::OpeningKey::new(&aead::AES_256_GCM, &key).map_err(|_| SecioError::KeyFileCorrupt)?;
+
+        let mut in_out = contents[cursor..].to_vec();
+        aead::open_in_place(&opening_key, nonce, &[], 0, &mut in_out)
+            .map_err(|_| SecioError::KeyFileCorrupt)?
+            .to_vec()
+    };
+
+    decode_payload(&payload)
+}
+
+/// Derives a 256-bit AES key from `passphrase` and `salt` via PBKDF2-HMAC-SHA256.
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::derive(&pbkdf2::HMAC_SHA256, PBKDF2_ITERATIONS, salt, passphrase.as_bytes(), &mut key);
+    key
+}
+
+fn encode_payload(key_pair: &SecioKeyPair) -> Result<Vec<u8>, SecioError> {
+    let mut out = Vec::new();
+    match key_pair.inner {
+        SecioKeyPairInner::Rsa { ref public, ref private_pkcs8, .. } => {
+            out.push(KEY_TYPE_RSA);
+            write_len_prefixed(&mut out, public);
+            write_len_prefixed(&mut out, private_pkcs8);
+        }
+        SecioKeyPairInner::Ed25519 { ref pkcs8, .. } => {
+            out.push(KEY_TYPE_ED25519);
+            write_len_prefixed(&mut out, pkcs8);
+        }
+        #[cfg(feature = "secp256k1")]
+        SecioKeyPairInner::Secp256k1 { ref private } => {
+            out.push(KEY_TYPE_SECP256K1);
+            out.extend_from_slice(&private[..]);
+        }
+    }
+    Ok(out)
+}
+
+fn decode_payload(payload: &[u8]) -> Result<SecioKeyPair, SecioError> {
+    if payload.is_empty() {
+        return Err(SecioError::KeyFileCorrupt);
+    }
+
+    match payload[0] {
+        KEY_TYPE_RSA => {
+            let mut cursor = 1;
+            let public = read_len_prefixed(payload, &mut cursor)?;
+            let private = read_len_prefixed(payload, &mut cursor)?;
+            SecioKeyPair::rsa_from_pkcs8(private, public).map_err(|_| SecioError::KeyFileCorrupt)
+        }
+        KEY_TYPE_ED25519 => {
+            let mut cursor = 1;
+            let pkcs8 = read_len_prefixed(payload, &mut cursor)?;
+            SecioKeyPair::ed25519_from_pkcs8(pkcs8).map_err(|_| SecioError::KeyFileCorrupt)
+        }
+        #[cfg(feature = "secp256k1")]
+        KEY_TYPE_SECP256K1 => {
+            if payload.len() != 1 + 32 {
+                return Err(SecioError::KeyFileCorrupt);
+            }
+            SecioKeyPair::secp256k1_raw_key(&payload[1..]).map_err(|_| SecioError::KeyFileCorrupt)
+        }
+        _ => Err(SecioError::KeyFileCorrupt),
+    }
+}
+
+fn write_len_prefixed(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn read_len_prefixed<'a>(data: &'a [u8], cursor: &mut usize) -> Result<&'a [u8], SecioError> {
+    if data.len() < *cursor + 4 {
+        return Err(SecioError::KeyFileCorrupt);
+    }
+    let mut len_buf = [0; 4];
+    len_buf.copy_from_slice(&data[*cursor..*cursor + 4]);
+    let len = u32::from_le_bytes(len_buf) as usize;
+    *cursor += 4;
+
+    if data.len() < *cursor + len {
+        return Err(SecioError::KeyFileCorrupt);
+    }
+    let slice = &data[*cursor..*cursor + len];
+    *cursor += len;
+    Ok(slice)
+}
+
+/// Restricts the key file to owner-only access (mode 0600). A failure is returned to the caller.
+#[cfg(unix)]
+fn set_private_permissions(path: &Path) -> Result<(), SecioError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(0o600);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_private_permissions(_path: &Path) -> Result<(), SecioError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> ::std::path::PathBuf {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("secio-persist-test-{}-{}-{}", name, ::std::process::id(), line!()));
+        path
+    }
+
+    fn public_key_bytes(key: &SecioKeyPair) -> Vec<u8> {
+        key.to_public_key().into_protobuf_encoding()
+    }
+
+    #[test]
+    fn round_trips_without_passphrase() {
+        let key = SecioKeyPair::ed25519_generated().unwrap();
+        let path = temp_path("plain");
+
+        save_pkcs8(&key, &path, None).unwrap();
+        let loaded = load_pkcs8(&path, None).unwrap();
+
+        assert_eq!(public_key_bytes(&key), public_key_bytes(&loaded));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn round_trips_with_passphrase() {
+        let key = SecioKeyPair::ed25519_generated().unwrap();
+        let path = temp_path("encrypted");
+
+        save_pkcs8(&key, &path, Some("correct horse battery staple")).unwrap();
+        let loaded = load_pkcs8(&path, Some("correct horse battery staple")).unwrap();
+
+        assert_eq!(public_key_bytes(&key), public_key_bytes(&loaded));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let key = SecioKeyPair::ed25519_generated().unwrap();
+        let path = temp_path("wrong-pass");
+        save_pkcs8(&key, &path, Some("right passphrase")).unwrap();
+
+        match load_pkcs8(&path, Some("wrong passphrase")) {
+            Err(SecioError::KeyFileCorrupt) => (),
+            other => panic!("expected KeyFileCorrupt, got {:?}", other),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn missing_passphrase_on_encrypted_file_is_rejected() {
+        let key = SecioKeyPair::ed25519_generated().unwrap();
+        let path = temp_path("needs-pass");
+        save_pkcs8(&key, &path, Some("a passphrase")).unwrap();
+
+        match load_pkcs8(&path, None) {
+            Err(SecioError::KeyFileCorrupt) => (),
+            other => panic!("expected KeyFileCorrupt, got {:?}", other),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_file_is_rejected() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"not a secio key file").unwrap();
+
+        match load_pkcs8(&path, None) {
+            Err(SecioError::KeyFileCorrupt) => (),
+            other => panic!("expected KeyFileCorrupt, got {:?}", other),
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
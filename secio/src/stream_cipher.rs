@@ -0,0 +1,317 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Negotiation and construction of the symmetric cipher used once the handshake has completed.
+
+use std::cmp::Ordering;
+
+use aes_ctr::stream_cipher::{NewStreamCipher, StreamCipher as _StreamCipherExt};
+use aes_ctr::{Aes128Ctr, Aes256Ctr};
+use ring::aead;
+
+use algo_support;
+use error::SecioError;
+
+/// Symmetric cipher algorithm negotiated during the handshake.
+///
+/// `Aes128` and `Aes256` are the original CTR-mode-plus-HMAC construction and are kept for
+/// backward compatibility; `Aes256Gcm` and `Chacha20Poly1305` are AEAD constructions where each
+/// frame carries its own authentication tag instead of relying on a separate HMAC pass.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Cipher {
+    Aes128,
+    Aes256,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    /// Name of this algorithm as it appears on the wire.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Cipher::Aes128 => "AES-128",
+            Cipher::Aes256 => "AES-256",
+            Cipher::Aes256Gcm => "AES-256-GCM",
+            Cipher::Chacha20Poly1305 => "ChaCha20Poly1305",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<Cipher> {
+        match name {
+            "AES-128" => Some(Cipher::Aes128),
+            "AES-256" => Some(Cipher::Aes256),
+            "AES-256-GCM" => Some(Cipher::Aes256Gcm),
+            "ChaCha20Poly1305" => Some(Cipher::Chacha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an AEAD construction (own per-frame tag, no separate HMAC) as opposed to
+    /// the CTR-mode-plus-HMAC construction.
+    pub fn is_aead(&self) -> bool {
+        match *self {
+            Cipher::Aes128 | Cipher::Aes256 => false,
+            Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => true,
+        }
+    }
+
+    /// Size, in bytes, of the key this cipher expects.
+    pub fn key_size(&self) -> usize {
+        match *self {
+            Cipher::Aes128 => 16,
+            Cipher::Aes256 | Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => 32,
+        }
+    }
+
+    /// Size, in bytes, of the IV this cipher expects. For the CTR ciphers this is the full IV;
+    /// for the AEAD ciphers this is the base nonce that the per-frame counter gets folded into.
+    pub fn iv_size(&self) -> usize {
+        match *self {
+            Cipher::Aes128 | Cipher::Aes256 => 16,
+            Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => 12,
+        }
+    }
+
+    /// Size, in bytes, of the authentication tag appended to each frame. Zero for the non-AEAD
+    /// ciphers, which rely on a separate HMAC instead.
+    pub fn tag_size(&self) -> usize {
+        match *self {
+            Cipher::Aes128 | Cipher::Aes256 => 0,
+            Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => 16,
+        }
+    }
+
+    /// The `ring` AEAD algorithm descriptor for this cipher.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `self.is_aead()` is `false`.
+    fn aead_algorithm(&self) -> &'static aead::Algorithm {
+        match *self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+            Cipher::Aes128 | Cipher::Aes256 => panic!("not an AEAD cipher"),
+        }
+    }
+}
+
+/// Default, ordered list of ciphers we propose if the user didn't override it. AEAD suites are
+/// preferred, but the CTR-plus-HMAC suites are still offered so that peers running an older
+/// implementation can still interoperate with us.
+pub fn default_ciphers() -> Vec<Cipher> {
+    vec![
+        Cipher::Aes256Gcm,
+        Cipher::Chacha20Poly1305,
+        Cipher::Aes256,
+        Cipher::Aes128,
+    ]
+}
+
+/// Negotiates the cipher to use, given the local and remote propositions.
+pub fn select_cipher(
+    ordering: Ordering,
+    local: &str,
+    remote: &str,
+) -> Result<Cipher, SecioError> {
+    let chosen = algo_support::select_best(ordering, local, remote)?;
+    Cipher::from_str(&chosen).ok_or(SecioError::HandshakeParsingFailure)
+}
+
+/// A symmetric stream cipher, abstracting over the various ciphers supported by `Cipher`.
+pub trait StreamCipher: Send {
+    /// Encrypts or decrypts (the operation is symmetric for a CTR-mode cipher) `data` in place.
+    fn apply_keystream(&mut self, data: &mut [u8]);
+}
+
+impl StreamCipher for Aes128Ctr {
+    #[inline]
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        _StreamCipherExt::apply_keystream(self, data)
+    }
+}
+
+impl StreamCipher for Aes256Ctr {
+    #[inline]
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        _StreamCipherExt::apply_keystream(self, data)
+    }
+}
+
+/// Builds the `StreamCipher` object corresponding to the given algorithm, key and IV.
+///
+/// # Panic
+///
+/// Panics if `cipher.is_aead()`, or if `key`/`iv` don't have the expected size for the given
+/// cipher.
+pub fn ctr(cipher: Cipher, key: &[u8], iv: &[u8]) -> Box<StreamCipher> {
+    match cipher {
+        Cipher::Aes128 => Box::new(
+            Aes128Ctr::new_var(key, iv).expect("key and iv have the size expected by Aes128Ctr"),
+        ),
+        Cipher::Aes256 => Box::new(
+            Aes256Ctr::new_var(key, iv).expect("key and iv have the size expected by Aes256Ctr"),
+        ),
+        Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => panic!("not a CTR cipher"),
+    }
+}
+
+/// One direction (encoding or decoding) of an AEAD cipher suite.
+///
+/// Each frame is encrypted or decrypted with a nonce derived by folding a monotonically
+/// increasing 64-bit counter into the low 8 bytes of `base_iv`, which was itself derived from the
+/// handshake's shared secret. The counter is purely local state, never sent on the wire, so a
+/// replayed or reordered frame will be decrypted against the wrong nonce and fail to
+/// authenticate.
+pub struct AeadCipher {
+    algorithm: &'static aead::Algorithm,
+    sealing_key: aead::SealingKey,
+    opening_key: aead::OpeningKey,
+    base_iv: [u8; 12],
+    counter: u64,
+}
+
+impl AeadCipher {
+    /// Builds the `AeadCipher` for the given cipher, key and base IV.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `cipher` is not an AEAD cipher, or if `key`/`base_iv` don't have the expected
+    /// size.
+    pub fn new(cipher: Cipher, key: &[u8], base_iv: &[u8]) -> AeadCipher {
+        assert!(cipher.is_aead(), "not an AEAD cipher");
+        assert_eq!(base_iv.len(), 12, "AEAD base IV must be 12 bytes");
+
+        let algorithm = cipher.aead_algorithm();
+        let sealing_key =
+            aead::SealingKey::new(algorithm, key).expect("key has the size expected by algorithm");
+        let opening_key =
+            aead::OpeningKey::new(algorithm, key).expect("key has the size expected by algorithm");
+
+        let mut fixed_iv = [0; 12];
+        fixed_iv.copy_from_slice(base_iv);
+
+        AeadCipher {
+            algorithm,
+            sealing_key,
+            opening_key,
+            base_iv: fixed_iv,
+            counter: 0,
+        }
+    }
+
+    /// Size, in bytes, of the tag this cipher appends to each frame.
+    pub fn tag_len(&self) -> usize {
+        self.algorithm.tag_len()
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.base_iv;
+        let counter_bytes = self.counter.to_be_bytes();
+        for (n, c) in nonce[4..].iter_mut().zip(counter_bytes.iter()) {
+            *n ^= *c;
+        }
+        self.counter = self.counter.checked_add(1).expect("frame counter overflowed");
+        nonce
+    }
+
+    /// Encrypts `plaintext_and_tag_space` in place. The slice must already have `tag_len()`
+    /// spare bytes at the end for the tag to be written into.
+    pub fn seal(&mut self, plaintext_and_tag_space: &mut [u8]) -> Result<(), SecioError> {
+        let nonce = self.next_nonce();
+        aead::seal_in_place(
+            &self.sealing_key,
+            &nonce,
+            &[],
+            plaintext_and_tag_space,
+            self.tag_len(),
+        ).map_err(|_| SecioError::CipherError)?;
+        Ok(())
+    }
+
+    /// Decrypts and verifies `ciphertext_and_tag` in place, returning the plaintext prefix.
+    pub fn open<'a>(&mut self, ciphertext_and_tag: &'a mut [u8]) -> Result<&'a mut [u8], SecioError> {
+        let nonce = self.next_nonce();
+        aead::open_in_place(&self.opening_key, &nonce, &[], 0, ciphertext_and_tag)
+            .map_err(|_| SecioError::CipherError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(cipher: Cipher) -> (AeadCipher, AeadCipher) {
+        let key = vec![0x42; cipher.key_size()];
+        let iv = vec![0x24; cipher.iv_size()];
+        (AeadCipher::new(cipher, &key, &iv), AeadCipher::new(cipher, &key, &iv))
+    }
+
+    #[test]
+    fn seal_then_open_roundtrips_in_order() {
+        for &cipher in &[Cipher::Aes256Gcm, Cipher::Chacha20Poly1305] {
+            let (mut sealer, mut opener) = pair(cipher);
+
+            for frame in &[&b"hello"[..], &b"world, this is secio"[..]] {
+                let mut buf = frame.to_vec();
+                buf.resize(buf.len() + sealer.tag_len(), 0);
+                sealer.seal(&mut buf).unwrap();
+
+                let opened = opener.open(&mut buf).unwrap();
+                assert_eq!(opened, *frame);
+            }
+        }
+    }
+
+    #[test]
+    fn open_rejects_reordered_frames() {
+        // The per-frame counter is purely local state: if the opener is handed the frames out of
+        // order, the nonce it derives for the first `open` call won't match the one the sealer
+        // used for that ciphertext, so authentication must fail.
+        let (mut sealer, mut opener) = pair(Cipher::Aes256Gcm);
+
+        let mut first = b"first frame".to_vec();
+        first.resize(first.len() + sealer.tag_len(), 0);
+        sealer.seal(&mut first).unwrap();
+
+        let mut second = b"second frame".to_vec();
+        second.resize(second.len() + sealer.tag_len(), 0);
+        sealer.seal(&mut second).unwrap();
+
+        // Deliver `second` first: the opener's counter is still at 0, but `second` was sealed
+        // with counter 1.
+        assert!(opener.open(&mut second).is_err());
+    }
+
+    #[test]
+    fn open_rejects_replayed_frame() {
+        let (mut sealer, mut opener) = pair(Cipher::Chacha20Poly1305);
+
+        let mut frame = b"replay me".to_vec();
+        frame.resize(frame.len() + sealer.tag_len(), 0);
+        sealer.seal(&mut frame).unwrap();
+        let original = frame.clone();
+
+        assert!(opener.open(&mut frame.clone()).is_ok());
+        // Replaying the exact same ciphertext again must fail: the opener's counter has already
+        // advanced past the nonce this frame was sealed with.
+        let mut replay = original;
+        assert!(opener.open(&mut replay).is_err());
+    }
+}
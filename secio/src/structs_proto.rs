@@ -0,0 +1,120 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Definitions of the two protobuf messages exchanged during the SECIO handshake.
+//!
+//! These mirror `structs.proto` from the go/js implementations closely enough to stay
+//! wire-compatible, but are hand-written rather than generated so that we don't need a protoc
+//! step in the build.
+
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufError};
+
+/// The first message exchanged during the handshake. Contains our nonce plus our proposal of
+/// supported key exchange / cipher / digest algorithms.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Propose {
+    pub rand: Vec<u8>,
+    pub pubkey: Vec<u8>,
+    pub exchanges: String,
+    pub ciphers: String,
+    pub hashes: String,
+}
+
+impl Propose {
+    pub fn write_to_bytes(&self) -> Result<Vec<u8>, ProtobufError> {
+        let mut out = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut out);
+            if !self.rand.is_empty() {
+                os.write_bytes(1, &self.rand)?;
+            }
+            if !self.pubkey.is_empty() {
+                os.write_bytes(2, &self.pubkey)?;
+            }
+            if !self.exchanges.is_empty() {
+                os.write_string(3, &self.exchanges)?;
+            }
+            if !self.ciphers.is_empty() {
+                os.write_string(4, &self.ciphers)?;
+            }
+            if !self.hashes.is_empty() {
+                os.write_string(5, &self.hashes)?;
+            }
+            os.flush()?;
+        }
+        Ok(out)
+    }
+
+    pub fn parse_from_bytes(bytes: &[u8]) -> Result<Propose, ProtobufError> {
+        let mut out = Propose::default();
+        let mut is = CodedInputStream::from_bytes(bytes);
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => out.rand = is.read_bytes()?,
+                2 => out.pubkey = is.read_bytes()?,
+                3 => out.exchanges = is.read_string()?,
+                4 => out.ciphers = is.read_string()?,
+                5 => out.hashes = is.read_string()?,
+                _ => is.skip_field(wire_type)?,
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// The second message exchanged during the handshake. Contains our ephemeral public key and a
+/// signature over the two concatenated `Propose` messages plus that key.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Exchange {
+    pub epubkey: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Exchange {
+    pub fn write_to_bytes(&self) -> Result<Vec<u8>, ProtobufError> {
+        let mut out = Vec::new();
+        {
+            let mut os = CodedOutputStream::vec(&mut out);
+            if !self.epubkey.is_empty() {
+                os.write_bytes(1, &self.epubkey)?;
+            }
+            if !self.signature.is_empty() {
+                os.write_bytes(2, &self.signature)?;
+            }
+            os.flush()?;
+        }
+        Ok(out)
+    }
+
+    pub fn parse_from_bytes(bytes: &[u8]) -> Result<Exchange, ProtobufError> {
+        let mut out = Exchange::default();
+        let mut is = CodedInputStream::from_bytes(bytes);
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => out.epubkey = is.read_bytes()?,
+                2 => out.signature = is.read_bytes()?,
+                _ => is.skip_field(wire_type)?,
+            }
+        }
+        Ok(out)
+    }
+}